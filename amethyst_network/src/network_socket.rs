@@ -1,36 +1,579 @@
 //! The network send and receive System
 
-use std::{clone::Clone, net::SocketAddr, thread};
+use std::{
+    clone::Clone,
+    collections::HashMap,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
-use amethyst_core::ecs::{Join, Resources, System, SystemData, WriteStorage, Entities};
+use amethyst_core::ecs::{
+    Component, Entities, Entity, HashMapStorage, Join, Resources, System, SystemData, WriteStorage,
+};
 
+use amethyst_core::shrev::{EventChannel, ReaderId};
 use crossbeam_channel::{Receiver, Sender};
 use laminar::{Packet, SocketEvent};
 use log::{error, warn};
-use serde::{de::DeserializeOwned, Serialize};
-
-use super::{
-    deserialize_event,
-    error::Result,
-    send_event,
-    server::{Host, ServerConfig},
-    ConnectionState, NetConnection, NetEvent, NetFilter,
-};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{deserialize_event, error::Result, server::Host, NetFilter};
 
 use log::info;
 
-enum InternalSocketEvent<E> {
-    SendEvents {
-        target: SocketAddr,
-        events: Vec<NetEvent<E>>,
+/// Controls how a `NetEvent` is delivered to its target by the underlying transport.
+///
+/// This mirrors the delivery guarantees laminar exposes on `Packet`, letting applications mix
+/// cheap unreliable updates (e.g. per-frame position) with reliable ones (e.g. state sync)
+/// on the same connection instead of paying reliable-ordered cost for everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryGuarantee {
+    /// Fire and forget; may arrive out of order, duplicated, or not at all.
+    UnreliableUnordered,
+    /// Like `UnreliableUnordered`, but older packets are dropped in favor of newer ones on the same stream.
+    UnreliableSequenced,
+    /// Guaranteed to arrive, but may arrive out of order.
+    ReliableUnordered,
+    /// Guaranteed to arrive, in the order it was sent.
+    ReliableOrdered,
+    /// Guaranteed to arrive, with older packets on the same stream dropped in favor of newer ones.
+    ReliableSequenced,
+}
+
+impl Default for DeliveryGuarantee {
+    /// Defaults to `ReliableOrdered`, matching the behaviour before per-event guarantees existed.
+    fn default() -> Self {
+        DeliveryGuarantee::ReliableOrdered
+    }
+}
+
+/// A `NetEvent` queued to be sent, tagged with how it should be delivered.
+///
+/// The `stream` id groups events that should be ordered/sequenced relative to each other but not
+/// relative to events on other streams; it is ignored by `UnreliableUnordered`/`ReliableUnordered`.
+#[derive(Clone)]
+pub struct OutgoingEvent<E> {
+    /// The event to send.
+    pub event: NetEvent<E>,
+    /// How the event should be delivered.
+    pub guarantee: DeliveryGuarantee,
+    /// The ordering/sequencing stream this event belongs to, if any.
+    pub stream: Option<u8>,
+}
+
+impl<E> OutgoingEvent<E> {
+    /// Wraps `event` for sending with the default delivery guarantee (`ReliableOrdered`).
+    pub fn new(event: NetEvent<E>) -> Self {
+        OutgoingEvent {
+            event,
+            guarantee: DeliveryGuarantee::default(),
+            stream: None,
+        }
+    }
+
+    /// Sets the delivery guarantee used to send this event.
+    pub fn with_guarantee(mut self, guarantee: DeliveryGuarantee) -> Self {
+        self.guarantee = guarantee;
+        self
+    }
+
+    /// Sets the ordering/sequencing stream this event belongs to.
+    pub fn with_stream(mut self, stream: u8) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+}
+
+/// Connection lifecycle state of a `NetConnection`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A handshake (`NetEvent::Connect`) has been sent or received, but no traffic confirming
+    /// the other side is there yet.
+    Connecting,
+    /// The handshake completed and traffic has been seen within `ServerConfig::connection_timeout`.
+    Connected,
+    /// No traffic has been seen within `ServerConfig::connection_timeout`, or the transport
+    /// reported the peer as gone.
+    Disconnected,
+}
+
+/// An event exchanged with a peer: either a connection lifecycle notification or an
+/// application-defined `E`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NetEvent<E> {
+    /// Requests a connection be established; the handshake event admission control looks for.
+    Connect,
+    /// `addr` completed its handshake.
+    Connected(SocketAddr),
+    /// `addr` disconnected, cleanly or otherwise.
+    Disconnected(SocketAddr),
+    /// A keepalive nudge sent on an idle connection; carries no payload.
+    Heartbeat,
+    /// Deserializing an incoming payload failed; carries the error message.
+    ConnectionError(String),
+    /// An application-defined event.
+    Message(E),
+}
+
+/// A single peer connection: its address, lifecycle state, and the buffers used to exchange
+/// `NetEvent<E>`s with it.
+///
+/// Sends are queued through [`NetConnection::send`]/[`NetConnection::send_with_guarantee`], not
+/// written to the transport directly, so `NetSocketSystem` can batch and dispatch them against
+/// whichever `Transport` it is driven by.
+pub struct NetConnection<E: 'static> {
+    /// The address this connection sends to, and is indexed by in `NetSocketSystem`.
+    pub target_addr: SocketAddr,
+    /// The connection's current lifecycle state.
+    pub state: ConnectionState,
+    send_buffer: EventChannel<OutgoingEvent<E>>,
+    send_reader: ReaderId<OutgoingEvent<E>>,
+    /// Events received from this peer, consumed by the application.
+    pub receive_buffer: EventChannel<NetEvent<E>>,
+}
+
+impl<E: 'static> NetConnection<E> {
+    /// Creates a new, `Connecting` connection to/from `target_addr` with empty buffers.
+    pub fn new(target_addr: SocketAddr) -> Self {
+        let mut send_buffer = EventChannel::new();
+        let send_reader = send_buffer.register_reader();
+        NetConnection {
+            target_addr,
+            state: ConnectionState::Connecting,
+            send_buffer,
+            send_reader,
+            receive_buffer: EventChannel::new(),
+        }
+    }
+
+    /// Queues `event` to be sent with the default delivery guarantee (`ReliableOrdered`).
+    pub fn send(&mut self, event: NetEvent<E>) {
+        self.send_buffer.single_write(OutgoingEvent::new(event));
+    }
+
+    /// Queues `event` to be sent with an explicit delivery guarantee.
+    pub fn send_with_guarantee(&mut self, event: NetEvent<E>, guarantee: DeliveryGuarantee) {
+        self.send_buffer
+            .single_write(OutgoingEvent::new(event).with_guarantee(guarantee));
+    }
+
+    /// Queues `event` to be sent with an explicit delivery guarantee on a given
+    /// ordering/sequencing stream (see [`OutgoingEvent::with_stream`]).
+    pub fn send_on_stream(&mut self, event: NetEvent<E>, guarantee: DeliveryGuarantee, stream: u8) {
+        self.send_buffer.single_write(
+            OutgoingEvent::new(event)
+                .with_guarantee(guarantee)
+                .with_stream(stream),
+        );
+    }
+
+    /// Drains the events queued since the last call, for `NetSocketSystem` to actually send.
+    pub(crate) fn send_buffer_early_read(&mut self) -> impl Iterator<Item = &OutgoingEvent<E>> {
+        self.send_buffer.read(&mut self.send_reader)
+    }
+}
+
+impl<E: PartialEq + Send + Sync + 'static> Component for NetConnection<E> {
+    // Connections are few relative to other components, so a sparse map beats a dense vec here.
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Lets applications allow or deny an unrecognized peer before a `NetConnection` entity is
+/// created for it, e.g. to implement an IP allow/deny list.
+pub trait ConnectionAcceptor: Send + Sync {
+    /// Returns `true` if a new `NetConnection` should be created for `addr`.
+    fn accept(&mut self, addr: SocketAddr) -> bool;
+}
+
+/// The default `ConnectionAcceptor`: admits every address that makes it past the connection
+/// cap and rate limiter.
+pub struct AllowAllAcceptor;
+
+impl ConnectionAcceptor for AllowAllAcceptor {
+    fn accept(&mut self, _addr: SocketAddr) -> bool {
+        true
+    }
+}
+
+/// A token-bucket rate limiter keyed by source address, used to cap how many admission
+/// attempts an unrecognized peer can make in a sliding window.
+struct TokenBucket {
+    tokens: u32,
+    window_start: Instant,
+}
+
+impl TokenBucket {
+    /// Starts with a full allowance of `capacity` tokens, so a brand new address's first
+    /// admission attempt doesn't have to wait out a full window before it can succeed.
+    fn new(capacity: u32) -> Self {
+        TokenBucket {
+            tokens: capacity,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// Refills to `capacity` once `window` has elapsed since the last refill, then tries to
+    /// take one token. Returns `false` if the bucket is empty.
+    fn try_consume(&mut self, capacity: u32, window: Duration) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= window {
+            self.tokens = capacity;
+            self.window_start = now;
+        }
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// True once `after` has elapsed since this bucket's window last reset. A stale bucket
+    /// belongs to an address that hasn't attempted to connect in a while, so it can be forgotten;
+    /// a later attempt just starts a fresh bucket with a full allowance, same as today.
+    fn is_stale(&self, now: Instant, after: Duration) -> bool {
+        now.duration_since(self.window_start) >= after
+    }
+}
+
+/// True once `timeout` has elapsed since `last_received`, meaning the keepalive pass should
+/// consider the peer gone. With no record of ever having received from this peer, the idle time
+/// counts as zero, so the peer is not idle (unless `timeout` is zero).
+fn is_idle(last_received: Option<Instant>, now: Instant, timeout: Duration) -> bool {
+    last_received
+        .map(|last| now.duration_since(last))
+        .unwrap_or(Duration::from_secs(0))
+        >= timeout
+}
+
+/// True if `interval` has elapsed since the last heartbeat was sent (or none ever was), meaning
+/// the keepalive pass should send another one now.
+fn is_due_for_heartbeat(last_heartbeat_sent: Option<Instant>, now: Instant, interval: Duration) -> bool {
+    last_heartbeat_sent
+        .map(|last| now.duration_since(last) >= interval)
+        .unwrap_or(true)
+}
+
+/// Deserializes `payload` and writes the resulting `NetEvent` (or a `ConnectionError` describing
+/// why deserialization failed) into `net_connection`'s receive buffer.
+fn deliver_incoming<E: DeserializeOwned>(net_connection: &mut NetConnection<E>, payload: &[u8]) {
+    match deserialize_event::<E>(payload) {
+        Ok(ev) => net_connection.receive_buffer.single_write(ev),
+        Err(e) => {
+            error!(
+                "Failed to deserialize an incoming network event: {} From source: {:?}",
+                e, net_connection.target_addr
+            );
+            net_connection
+                .receive_buffer
+                .single_write(NetEvent::ConnectionError(e.to_string()));
+        }
+    }
+}
+
+/// Builds the `laminar::Packet` matching `guarantee` for an already-serialized `payload`.
+fn build_laminar_packet(
+    payload: Vec<u8>,
+    target: SocketAddr,
+    guarantee: DeliveryGuarantee,
+    stream: Option<u8>,
+) -> Packet {
+    match guarantee {
+        DeliveryGuarantee::UnreliableUnordered => Packet::unreliable(target, payload),
+        DeliveryGuarantee::UnreliableSequenced => {
+            Packet::unreliable_sequenced(target, payload, stream)
+        }
+        DeliveryGuarantee::ReliableUnordered => Packet::reliable_unordered(target, payload),
+        DeliveryGuarantee::ReliableOrdered => Packet::reliable_ordered(target, payload, stream),
+        DeliveryGuarantee::ReliableSequenced => {
+            Packet::reliable_sequenced(target, payload, stream)
+        }
+    }
+}
+
+/// A transport-agnostic outgoing message: a serialized payload bound for `target`, tagged with
+/// how it should be delivered.
+pub struct TransportPacket {
+    /// The peer this packet is addressed to.
+    pub target: SocketAddr,
+    /// The already-serialized `NetEvent`.
+    pub payload: Vec<u8>,
+    /// How the packet should be delivered. A stream-based transport may not be able to honor
+    /// every variant and can fall back to its own closest guarantee.
+    pub guarantee: DeliveryGuarantee,
+    /// The ordering/sequencing stream this packet belongs to, if any.
+    pub stream: Option<u8>,
+}
+
+/// A transport-agnostic incoming event. `Data` carries a payload to be deserialized into an
+/// `E`; the rest are connection lifecycle notifications a `Transport` surfaces even though they
+/// carry no payload of their own.
+pub enum TransportEvent {
+    /// A payload arrived from `addr`.
+    Data {
+        /// The peer the payload arrived from.
+        addr: SocketAddr,
+        /// The raw, not-yet-deserialized payload.
+        payload: Vec<u8>,
     },
-    Stop,
+    /// `addr` completed a handshake/connect.
+    Connected(SocketAddr),
+    /// `addr` disconnected, cleanly or otherwise.
+    Disconnected(SocketAddr),
+    /// `addr` stopped responding and was dropped for inactivity.
+    TimedOut(SocketAddr),
+}
+
+/// Configuration for a `NetSocketSystem` and the `Transport` it drives.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The local address to bind/listen on.
+    pub udp_socket_addr: SocketAddr,
+    /// Hard cap on concurrently live (`Connecting`/`Connected`) connections; see
+    /// `NetSocketSystem::should_admit`.
+    pub max_connections: usize,
+    /// If `true`, an unrecognized address must send `NetEvent::Connect` before anything else is
+    /// accepted from it.
+    pub require_handshake: bool,
+    /// Max admission attempts a single unrecognized address gets per `connection_rate_window`.
+    pub connection_rate_limit: u32,
+    /// The sliding window `connection_rate_limit` is measured over.
+    pub connection_rate_window: Duration,
+    /// How long a connection may go without any traffic before it's considered gone.
+    pub connection_timeout: Duration,
+    /// How often to nudge an otherwise-idle connection with a heartbeat.
+    pub heartbeat_interval: Duration,
+    /// Upper bound on in-flight payloads/events buffered between a `Transport`'s background
+    /// thread(s) and `NetSocketSystem::run`.
+    pub max_throughput: u32,
+}
+
+/// A network backend `NetSocketSystem` can be driven by, in place of the default laminar/UDP one
+/// (e.g. a TCP or WebSocket transport for browser or reliable-stream clients).
+///
+/// This replaces the fragile assumption the original implementation baked in - that a peer
+/// always receives on the same address it sent from - with whatever connection identity makes
+/// sense for the backend, as long as it stays stable as a `SocketAddr` for the life of a session.
+pub trait Transport: Send + 'static {
+    /// Binds/connects per `config` and spawns whatever background threads it needs.
+    fn start(config: &ServerConfig) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Queues `packet` to be sent. Transports that cannot honor `packet.guarantee` (e.g. a pure
+    /// TCP stream) should fall back to their closest equivalent rather than erroring.
+    fn send(&self, packet: TransportPacket);
+
+    /// A receiver of incoming payloads and connection lifecycle events. Cheap to call repeatedly;
+    /// implementations should return a clone of an internally held `Receiver`.
+    fn receiver(&self) -> Receiver<TransportEvent>;
+}
+
+/// The default `Transport`: laminar over UDP, preserving `NetSocketSystem`'s original behaviour.
+pub struct LaminarTransport {
+    sender: Sender<TransportPacket>,
+    receiver: Receiver<TransportEvent>,
+}
+
+impl Transport for LaminarTransport {
+    fn start(config: &ServerConfig) -> Result<Self> {
+        let server = Host::run(config)?;
+
+        let sender = LaminarTransport::start_sending(server.udp_send_handle());
+        let receiver =
+            LaminarTransport::start_receiving(server.udp_receive_handle(), config.max_throughput as usize);
+
+        Ok(LaminarTransport { sender, receiver })
+    }
+
+    fn send(&self, packet: TransportPacket) {
+        if let Err(error) = self.sender.send(packet) {
+            error!("`LaminarTransport`'s send thread is gone: {:?}", error);
+        }
+    }
+
+    fn receiver(&self) -> Receiver<TransportEvent> {
+        self.receiver.clone()
+    }
+}
+
+impl LaminarTransport {
+    /// Start a thread to send all queued packets.
+    fn start_sending(sender: Sender<Packet>) -> Sender<TransportPacket> {
+        let (tx, send_queue) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            for packet in send_queue.iter() {
+                let laminar_packet =
+                    build_laminar_packet(packet.payload, packet.target, packet.guarantee, packet.stream);
+                if let Err(error) = sender.send(laminar_packet) {
+                    error!("Failed to send a packet: {:?}", error);
+                }
+            }
+        });
+
+        tx
+    }
+
+    /// Starts a thread which receives incoming laminar events and translates them into
+    /// transport-agnostic ones.
+    ///
+    /// The channel is bounded by `capacity` (`ServerConfig::max_throughput`), so a
+    /// `NetSocketSystem` that falls behind applies real backpressure to this thread (it blocks
+    /// on `tx.send`) instead of the old hard per-run cutoff that silently deferred packets.
+    fn start_receiving(receiver: Receiver<SocketEvent>, capacity: usize) -> Receiver<TransportEvent> {
+        let (tx, rx) = crossbeam_channel::bounded(capacity);
+
+        thread::spawn(move || {
+            for event in receiver.iter() {
+                let translated = match event {
+                    SocketEvent::Packet(packet) => TransportEvent::Data {
+                        addr: packet.addr(),
+                        payload: packet.payload().to_vec(),
+                    },
+                    SocketEvent::Connect(addr) => TransportEvent::Connected(addr),
+                    SocketEvent::Timeout(addr) => TransportEvent::TimedOut(addr),
+                    SocketEvent::Disconnect(addr) => TransportEvent::Disconnected(addr),
+                };
+                if let Err(error) = tx.send(translated) {
+                    error!("`NetworkSocketSystem` was dropped. Reason: {:?}", error);
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+/// A TCP-based `Transport`, for browsers and other clients that want a single reliable, ordered
+/// stream rather than laminar's reliable/unreliable mix over UDP.
+///
+/// Every packet is sent reliably and in order regardless of `DeliveryGuarantee`, since that is
+/// the only guarantee a byte stream can offer without building a datagram layer on top of it.
+/// Connection identity is the peer's `SocketAddr`, which - unlike a UDP source address - is
+/// stable for the life of the TCP connection.
+pub struct TcpTransport {
+    streams: Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
+    receiver: Receiver<TransportEvent>,
+}
+
+impl Transport for TcpTransport {
+    fn start(config: &ServerConfig) -> Result<Self> {
+        let listener = TcpListener::bind(config.udp_socket_addr)?;
+        let streams = Arc::new(Mutex::new(HashMap::new()));
+        // Bounded the same way as `LaminarTransport`, so a slow `NetSocketSystem` applies
+        // backpressure here too instead of growing this channel without bound.
+        let (tx, rx) = crossbeam_channel::bounded(config.max_throughput as usize);
+
+        let accept_streams = Arc::clone(&streams);
+        thread::spawn(move || {
+            for incoming in listener.incoming() {
+                let stream = match incoming {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        error!("TCP accept failed: {:?}", error);
+                        continue;
+                    }
+                };
+                let addr = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(error) => {
+                        error!("Accepted TCP connection had no peer address: {:?}", error);
+                        continue;
+                    }
+                };
+                let reader_stream = match stream.try_clone() {
+                    Ok(reader_stream) => reader_stream,
+                    Err(error) => {
+                        error!("Failed to clone TCP stream for {:?}: {:?}", addr, error);
+                        continue;
+                    }
+                };
+                accept_streams.lock().unwrap().insert(addr, stream);
+                if tx.send(TransportEvent::Connected(addr)).is_err() {
+                    break;
+                }
+                TcpTransport::spawn_reader(addr, reader_stream, tx.clone(), Arc::clone(&accept_streams));
+            }
+        });
+
+        Ok(TcpTransport { streams, receiver: rx })
+    }
+
+    fn send(&self, packet: TransportPacket) {
+        // Only hold the lock long enough to clone the stream's file descriptor (cheap, never
+        // blocks on the peer); the actual write happens after it's released, so one stalled peer
+        // with a full send buffer can't stall sends to every other peer on the same tick.
+        let stream = self.streams.lock().unwrap().get(&packet.target).map(TcpStream::try_clone);
+
+        let mut stream = match stream {
+            Some(Ok(stream)) => stream,
+            Some(Err(error)) => {
+                error!("Failed to clone TCP stream for {:?}: {:?}", packet.target, error);
+                return;
+            }
+            None => {
+                warn!("No TCP connection open to {:?}", packet.target);
+                return;
+            }
+        };
+
+        let len = (packet.payload.len() as u32).to_be_bytes();
+        let failed = stream
+            .write_all(&len)
+            .and_then(|_| stream.write_all(&packet.payload))
+            .is_err();
+
+        if failed {
+            error!("Failed to write to TCP peer {:?}, dropping it", packet.target);
+            self.streams.lock().unwrap().remove(&packet.target);
+        }
+    }
+
+    fn receiver(&self) -> Receiver<TransportEvent> {
+        self.receiver.clone()
+    }
+}
+
+impl TcpTransport {
+    /// Reads length-prefixed frames from `stream` until it closes or errors, forwarding each as
+    /// a `TransportEvent::Data`.
+    fn spawn_reader(
+        addr: SocketAddr,
+        mut stream: TcpStream,
+        tx: Sender<TransportEvent>,
+        streams: Arc<Mutex<HashMap<SocketAddr, TcpStream>>>,
+    ) {
+        thread::spawn(move || {
+            let mut len_buf = [0u8; 4];
+            loop {
+                if stream.read_exact(&mut len_buf).is_err() {
+                    break;
+                }
+                let len = u32::from_be_bytes(len_buf) as usize;
+                let mut payload = vec![0u8; len];
+                if stream.read_exact(&mut payload).is_err() {
+                    break;
+                }
+                if tx.send(TransportEvent::Data { addr, payload }).is_err() {
+                    break;
+                }
+            }
+            streams.lock().unwrap().remove(&addr);
+            let _ = tx.send(TransportEvent::Disconnected(addr));
+        });
+    }
 }
 
 // If a client sends both a connect event and other events,
 // only the connect event will be considered valid and all others will be lost.
 /// The System managing the network state and connections.
-/// The T generic parameter corresponds to the network event type.
+/// The E generic parameter corresponds to the network event type.
+/// The T generic parameter selects the `Transport` backend (laminar/UDP by default).
 /// Receives events and filters them.
 /// Received events will be inserted into the NetReceiveBuffer resource.
 /// To send an event, add it to the NetSendBuffer resource.
@@ -39,91 +582,125 @@ enum InternalSocketEvent<E> {
 /// only the connection event will be considered and rest will be filtered out.
 // TODO: add Unchecked Event type list. Those events will be let pass the client connected filter (Example: NetEvent::Connect).
 // Current behaviour: hardcoded passthrough of Connect and Connected events.
-pub struct NetSocketSystem<E: 'static>
+pub struct NetSocketSystem<E: 'static, T: Transport = LaminarTransport>
 where
     E: PartialEq,
 {
     /// The list of filters applied on the events received.
     pub filters: Vec<Box<dyn NetFilter<E>>>,
-    // sender on which you can queue packets to send to some endpoint.
-    transport_sender: Sender<InternalSocketEvent<E>>,
-    // receiver from which you can read received packets.
-    transport_receiver: Receiver<Packet>,
+    // the transport backend packets are sent/received through; laminar/UDP by default.
+    transport: T,
+    // receiver from which you can read received packets and connection lifecycle events.
+    transport_receiver: Receiver<TransportEvent>,
     config: ServerConfig,
+    // Last time any traffic (packet, connect, or heartbeat reply) was seen from a given peer.
+    // Used to detect idle connections that laminar itself won't tell us about.
+    last_received: HashMap<SocketAddr, Instant>,
+    // Last time a heartbeat was sent to a given peer, so we don't flood it faster than
+    // `config.heartbeat_interval`.
+    last_heartbeat_sent: HashMap<SocketAddr, Instant>,
+    // Per-source-address admission rate limiter, guards against a flood of unrecognized
+    // addresses each trying to allocate a `NetConnection`.
+    rate_limiter: HashMap<SocketAddr, TokenBucket>,
+    // Pluggable admission policy consulted after the cap and rate limiter pass.
+    acceptor: Box<dyn ConnectionAcceptor>,
+    // Routes an incoming address straight to its `NetConnection` entity in O(1), instead of
+    // scanning every connection per packet. Updated incrementally as connections are admitted
+    // or torn down; synced once per `run` against any connections an application added directly
+    // (e.g. for an outgoing connection) since the last tick.
+    connection_index: HashMap<SocketAddr, Entity>,
 }
 
-impl<E> NetSocketSystem<E>
+impl<E, T: Transport> NetSocketSystem<E, T>
 where
     E: Serialize + PartialEq + Send + 'static,
 {
-    /// Creates a `NetSocketSystem` and binds the Socket on the ip and port added in parameters.
+    /// Creates a `NetSocketSystem` and starts its transport (by default, binding the UDP socket
+    /// on the ip and port given in `config`).
     pub fn new(config: ServerConfig, filters: Vec<Box<dyn NetFilter<E>>>) -> Result<Self> {
         if config.udp_socket_addr.port() < 1024 {
             // Just warning the user here, just in case they want to use the root port.
             warn!("Using a port below 1024, this will require root permission and should not be done.");
         }
 
-        let server = Host::run(&config)?;
-
-        let udp_send_handle = server.udp_send_handle();
-        let udp_receive_handle = server.udp_receive_handle();
-
-        let server_sender = NetSocketSystem::<E>::start_sending(udp_send_handle);
-        let server_receiver = NetSocketSystem::<E>::start_receiving(udp_receive_handle);
+        let transport = T::start(&config)?;
+        let transport_receiver = transport.receiver();
 
         Ok(NetSocketSystem {
             filters,
-            transport_sender: server_sender,
-            transport_receiver: server_receiver,
+            transport,
+            transport_receiver,
             config,
+            last_received: HashMap::new(),
+            last_heartbeat_sent: HashMap::new(),
+            rate_limiter: HashMap::new(),
+            acceptor: Box::new(AllowAllAcceptor),
+            connection_index: HashMap::new(),
         })
     }
 
-    /// Start a thread to send all queued packets.
-    fn start_sending(sender: Sender<Packet>) -> Sender<InternalSocketEvent<E>> {
-        let (tx, send_queue) = crossbeam_channel::unbounded();
+    /// Replaces the admission policy used for unrecognized peers, e.g. with an IP allow/deny list.
+    pub fn with_acceptor(mut self, acceptor: Box<dyn ConnectionAcceptor>) -> Self {
+        self.acceptor = acceptor;
+        self
+    }
 
-        thread::spawn(move || loop {
-            for control_event in send_queue.try_iter() {
-                match control_event {
-                    InternalSocketEvent::SendEvents { target, events } => {
-                        for ev in events {
-                            send_event(ev, target, &sender);
-                        }
-                    }
-                    InternalSocketEvent::Stop => {
-                        break;
-                    }
-                }
-            }
-        });
+    /// Decides whether an unrecognized `addr` should get a brand new `NetConnection`, applying
+    /// (in order) the connection cap, the handshake requirement, the rate limiter, and finally
+    /// the pluggable `ConnectionAcceptor`.
+    ///
+    /// `live_connection_count` must count only `Connecting`/`Connected` connections, not every
+    /// address ever admitted: `connection_index` keeps a `Disconnected` entry around forever so a
+    /// reconnecting peer finds its old entity instead of duplicating it (see `NetSocketSystem::run`),
+    /// so its length alone would make the cap count every peer that has ever passed through.
+    fn should_admit(&mut self, addr: SocketAddr, live_connection_count: usize, is_handshake: bool) -> bool {
+        if live_connection_count >= self.config.max_connections {
+            warn!(
+                "Rejecting new connection from {:?}: max_connections ({}) reached",
+                addr, self.config.max_connections
+            );
+            return false;
+        }
 
-        tx
-    }
+        if self.config.require_handshake && !is_handshake {
+            warn!(
+                "Rejecting packet from unrecognized {:?}: no handshake (NetEvent::Connect) seen",
+                addr
+            );
+            return false;
+        }
 
-    /// Starts a thread which receives incoming packets and sends them onto the 'Receiver' channel.
-    fn start_receiving(receiver: Receiver<SocketEvent>) -> Receiver<Packet> {
-        let (receive_queue, rx) = crossbeam_channel::unbounded();
+        let capacity = self.config.connection_rate_limit;
+        let bucket = self
+            .rate_limiter
+            .entry(addr)
+            .or_insert_with(|| TokenBucket::new(capacity));
+        if !bucket.try_consume(self.config.connection_rate_limit, self.config.connection_rate_window) {
+            warn!("Rejecting connection from {:?}: rate limit exceeded", addr);
+            return false;
+        }
 
-        thread::spawn(move || loop {
-            for event in receiver.iter() {
-                match event {
-                    SocketEvent::Packet(packet) => {
-                        if let Err(error) = receive_queue.send(packet.clone()) {
-                            error!("`NetworkSocketSystem` was dropped. Reason: {:?}", error);
-                            break;
-                        }
-                    }
-                    _ => error!("Event not supported"),
-                }
-            }
-        });
+        if !self.acceptor.accept(addr) {
+            warn!("Rejecting connection from {:?}: denied by ConnectionAcceptor", addr);
+            return false;
+        }
 
-        rx
+        true
+    }
+
+    /// Serializes `outgoing.event` into a `TransportPacket` addressed to `target`.
+    fn to_transport_packet(target: SocketAddr, outgoing: OutgoingEvent<E>) -> Result<TransportPacket> {
+        let payload = bincode::serialize(&outgoing.event)?;
+        Ok(TransportPacket {
+            target,
+            payload,
+            guarantee: outgoing.guarantee,
+            stream: outgoing.stream,
+        })
     }
 }
 
-impl<'a, E> System<'a> for NetSocketSystem<E>
+impl<'a, E, T: Transport> System<'a> for NetSocketSystem<E, T>
 where
     E: Send + Sync + Serialize + Clone + DeserializeOwned + PartialEq + 'static,
 {
@@ -133,71 +710,190 @@ where
     );
 
     fn run(&mut self, (entities, mut net_connections): Self::SystemData) {
+        // Sync the address -> entity index against any connections an application added
+        // directly (e.g. for an outgoing connection) since the last tick. This is O(connections),
+        // not O(packets x connections) like the old double-scan in the receive loop below was.
+        for (entity, net_connection) in (&entities, &net_connections).join() {
+            self.connection_index.entry(net_connection.target_addr).or_insert(entity);
+        }
+
         for net_connection in (&mut net_connections).join() {
             let target = net_connection.target_addr;
 
             if net_connection.state == ConnectionState::Connected
                 || net_connection.state == ConnectionState::Connecting
             {
-                self.transport_sender
-                    .send(InternalSocketEvent::SendEvents {
-                        target,
-                        events: net_connection.send_buffer_early_read().cloned().collect(),
-                    })
-                    .expect("Unreachable: Channel will be alive until a stop event is sent");
-            } else if net_connection.state == ConnectionState::Disconnected {
-                self.transport_sender
-                    .send(InternalSocketEvent::Stop)
-                    .expect("Already sent a stop event to the channel");
+                // Each queued `OutgoingEvent` already carries the guarantee/stream the caller
+                // picked via `NetConnection::send_with_guarantee`/`send_on_stream`; just forward it.
+                for outgoing in net_connection.send_buffer_early_read().cloned() {
+                    match Self::to_transport_packet(target, outgoing) {
+                        Ok(packet) => self.transport.send(packet),
+                        Err(error) => error!("Failed to serialize an outgoing network event: {}", error),
+                    }
+                }
+            }
+        }
+
+        // Forget rate-limiter buckets that haven't seen an admission attempt in a while. Unlike
+        // `last_received`/`last_heartbeat_sent`/`rate_limiter` entries for addresses that became a
+        // tracked `NetConnection` (cleaned up on disconnect/timeout elsewhere in this function), a
+        // spoofed, rate-limited, or `ConnectionAcceptor`-denied address never becomes one, so
+        // without this its bucket would stay here, taking attacker-controlled unbounded memory.
+        let now = Instant::now();
+        let stale_after = self.config.connection_rate_window * 2;
+        self.rate_limiter.retain(|_, bucket| !bucket.is_stale(now, stale_after));
+
+        // Keepalive pass: detect peers that have gone quiet, and nudge the ones that are still
+        // within their timeout window so laminar keeps punching through NAT/firewalls.
+        for net_connection in (&mut net_connections).join() {
+            let target = net_connection.target_addr;
+            if net_connection.state != ConnectionState::Connecting
+                && net_connection.state != ConnectionState::Connected
+            {
+                continue;
+            }
+
+            let idle = is_idle(
+                self.last_received.get(&target).copied(),
+                now,
+                self.config.connection_timeout,
+            );
+
+            if idle {
+                net_connection.state = ConnectionState::Disconnected;
+                net_connection
+                    .receive_buffer
+                    .single_write(NetEvent::Disconnected(target));
+                // Same per-address bookkeeping cleanup as the transport-level Disconnected/TimedOut
+                // path below, so a peer that goes quiet and never reconnects doesn't leak an entry
+                // in each of these maps for the life of the process.
+                self.last_received.remove(&target);
+                self.last_heartbeat_sent.remove(&target);
+                self.rate_limiter.remove(&target);
+                // Deliberately left in `connection_index`: the entity still exists and should be
+                // found (and reconnected in place) if this same address sends again, rather than
+                // falling through the `should_admit` path and getting a duplicate NetConnection.
+                continue;
+            }
+
+            let due_for_heartbeat = is_due_for_heartbeat(
+                self.last_heartbeat_sent.get(&target).copied(),
+                now,
+                self.config.heartbeat_interval,
+            );
+
+            if due_for_heartbeat {
+                self.last_heartbeat_sent.insert(target, now);
+                let heartbeat = OutgoingEvent::new(NetEvent::Heartbeat)
+                    .with_guarantee(DeliveryGuarantee::UnreliableUnordered);
+                match Self::to_transport_packet(target, heartbeat) {
+                    Ok(packet) => self.transport.send(packet),
+                    Err(error) => error!("Failed to serialize a heartbeat: {}", error),
+                }
             }
         }
 
-        for (counter, raw_event) in self.transport_receiver.try_iter().enumerate() {
-	        // Do it twice to collect from activated connections
-            for _ in 0..2 {
-                let mut matched = false;
-                // Get the NetConnection from the source
-                for net_connection in (&mut net_connections).join() {
-                    // We found the origin
-                    if net_connection.target_addr == raw_event.addr() {
-                        matched = true;
-                        // Get the event
-                        match deserialize_event::<E>(raw_event.payload()) {
-                            Ok(ev) => {
-                                net_connection.receive_buffer.single_write(ev);
+        // Routes each incoming event via `connection_index` in O(1), rather than scanning every
+        // `NetConnection` per packet (and doing it twice, to catch connections admitted earlier
+        // in this same drain) like the old implementation did. A newly-admitted source is
+        // inserted into the index immediately, so later events in this same drain route to it
+        // without needing a second pass. The channel itself is bounded (see
+        // `LaminarTransport::start_receiving`), so draining it fully here is safe backpressure
+        // rather than the old hard per-run cutoff that silently deferred packets.
+        for raw_event in self.transport_receiver.try_iter() {
+            match raw_event {
+                TransportEvent::Data { addr, payload } => {
+                    if let Some(net_connection) = self
+                        .connection_index
+                        .get(&addr)
+                        .and_then(|&entity| net_connections.get_mut(entity))
+                    {
+                        self.last_received.insert(addr, Instant::now());
+                        if net_connection.state != ConnectionState::Connected {
+                            net_connection.state = ConnectionState::Connected;
+                        }
+                        deliver_incoming(net_connection, &payload);
+                    } else {
+                        // Instead of just complaining about missing this source we are going to make a
+                        // new NetConnection to receive from this source, subject to admission control.
+                        // `connection_index.len()` would count every address ever admitted, since
+                        // `Disconnected` entries are deliberately kept in it; count only live ones.
+                        let connection_count = (&net_connections)
+                            .join()
+                            .filter(|c| c.state != ConnectionState::Disconnected)
+                            .count();
+                        let is_handshake = deserialize_event::<E>(&payload)
+                            .map(|ev| matches!(ev, NetEvent::Connect))
+                            .unwrap_or(false);
+                        if self.should_admit(addr, connection_count, is_handshake) {
+                            // We need to assume the target will receive from the same address as they sent from, perhaps a (TODO) proper connection builder would send the recieve address as the next packet
+                            let entity = entities
+                                .build_entity()
+                                .with(NetConnection::<E>::new(addr), &mut net_connections)
+                                .build();
+                            self.connection_index.insert(addr, entity);
+                            // Without this, a connection admitted from a single bare packet that
+                            // never sends again has no `last_received` entry, so `is_idle` counts
+                            // its idle time as zero forever and the keepalive pass never times it out.
+                            self.last_received.insert(addr, Instant::now());
+                            // Deliver the very packet that triggered admission, the same way the
+                            // old double-scan used to re-deliver it on its second pass over every
+                            // `NetConnection` once the entity existed.
+                            if let Some(net_connection) = net_connections.get_mut(entity) {
+                                net_connection.state = ConnectionState::Connected;
+                                deliver_incoming(net_connection, &payload);
                             }
-                            Err(e) => error!(
-                                "Failed to deserialize an incoming network event: {} From source: {:?}",
-                                e,
-                                raw_event.addr()
-                            ),
                         }
-                        // No two NetConnections can share a target
-                        break;
-	                }
+                    }
                 }
-                if !matched {
-                    // Instead of just complaining about missing this source we are going to make a
-                    // new NetConnection to receive from this source
-                    // TODO: This is of course susceptible to DoS so uhhhh we need to deal with that
-                    // Bring in the entities so that we can add a NetConnection
-                    info!("MAKING A NETCONNECTION!!!! LOL GREP FOR XDXD");
-                    entities.build_entity()
-	                    // We need to assume the target will receive from the same address as they sent from, perhaps a (TODO) proper connection builder would send the recieve address as the next packet
-                        .with(NetConnection::<E>::new(raw_event.addr()), &mut net_connections)
-                        .build();
+                TransportEvent::Connected(addr) => {
+                    self.last_received.insert(addr, Instant::now());
+                    if let Some(net_connection) = self
+                        .connection_index
+                        .get(&addr)
+                        .and_then(|&entity| net_connections.get_mut(entity))
+                    {
+                        net_connection.state = ConnectionState::Connected;
+                        net_connection.receive_buffer.single_write(NetEvent::Connected(addr));
+                    } else {
+                        // Same live-count rationale as the `Data` admission branch above.
+                        let connection_count = (&net_connections)
+                            .join()
+                            .filter(|c| c.state != ConnectionState::Disconnected)
+                            .count();
+                        if self.should_admit(addr, connection_count, true) {
+                            info!("Peer {:?} connected before a NetConnection existed for it", addr);
+                            let entity = entities
+                                .build_entity()
+                                .with(NetConnection::<E>::new(addr), &mut net_connections)
+                                .build();
+                            self.connection_index.insert(addr, entity);
+                            // Tell the new connection about its own connect event, the same way
+                            // the old double-scan used to once the entity existed.
+                            if let Some(net_connection) = net_connections.get_mut(entity) {
+                                net_connection.state = ConnectionState::Connected;
+                                net_connection.receive_buffer.single_write(NetEvent::Connected(addr));
+                            }
+                        }
+                    }
                 }
-                else {
-                    break
+                TransportEvent::Disconnected(addr) | TransportEvent::TimedOut(addr) => {
+                    self.last_received.remove(&addr);
+                    self.last_heartbeat_sent.remove(&addr);
+                    self.rate_limiter.remove(&addr);
+                    // `addr` stays in `connection_index`: the NetConnection entity is still
+                    // around (just `Disconnected`), so later traffic from the same address
+                    // should find and reconnect it instead of spawning a duplicate entity.
+                    if let Some(net_connection) = self
+                        .connection_index
+                        .get(&addr)
+                        .and_then(|&entity| net_connections.get_mut(entity))
+                    {
+                        net_connection.state = ConnectionState::Disconnected;
+                        net_connection.receive_buffer.single_write(NetEvent::Disconnected(addr));
+                    }
                 }
             }
-
-            // this will prevent our system to be stuck in the iterator.
-            // After 10000 packets we will continue and leave the other packets for the next run.
-            // eventually some congestion prevention should be done.
-            if counter >= self.config.max_throughput as usize {
-                break;
-            }
         }
         info!("end of NS::run");
     }
@@ -206,3 +902,211 @@ where
         Self::SystemData::setup(res);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:12345".parse().unwrap()
+    }
+
+    fn addr2() -> SocketAddr {
+        "127.0.0.1:12346".parse().unwrap()
+    }
+
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            udp_socket_addr: "127.0.0.1:0".parse().unwrap(),
+            max_connections: 2,
+            require_handshake: false,
+            connection_rate_limit: 2,
+            connection_rate_window: Duration::from_secs(60),
+            connection_timeout: Duration::from_secs(30),
+            heartbeat_interval: Duration::from_secs(5),
+            max_throughput: 64,
+        }
+    }
+
+    /// A no-op `Transport` that discards whatever is sent, for driving `NetSocketSystem` in tests
+    /// without any real I/O.
+    struct NullTransport {
+        receiver: Receiver<TransportEvent>,
+    }
+
+    impl Transport for NullTransport {
+        fn start(_config: &ServerConfig) -> Result<Self> {
+            let (_tx, rx) = crossbeam_channel::unbounded();
+            Ok(NullTransport { receiver: rx })
+        }
+
+        fn send(&self, _packet: TransportPacket) {}
+
+        fn receiver(&self) -> Receiver<TransportEvent> {
+            self.receiver.clone()
+        }
+    }
+
+    /// Builds a `NetSocketSystem` around a `NullTransport`, bypassing `new()` (which would bind a
+    /// real socket) the same way `NetConnection::new` is used directly in `run`'s admission paths.
+    fn test_system() -> NetSocketSystem<(), NullTransport> {
+        let config = test_config();
+        let transport = NullTransport::start(&config).unwrap();
+        let transport_receiver = transport.receiver();
+        NetSocketSystem {
+            filters: Vec::new(),
+            transport,
+            transport_receiver,
+            config,
+            last_received: HashMap::new(),
+            last_heartbeat_sent: HashMap::new(),
+            rate_limiter: HashMap::new(),
+            acceptor: Box::new(AllowAllAcceptor),
+            connection_index: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn build_laminar_packet_preserves_target_and_payload_for_every_guarantee() {
+        let payload = vec![1, 2, 3];
+        for guarantee in [
+            DeliveryGuarantee::UnreliableUnordered,
+            DeliveryGuarantee::UnreliableSequenced,
+            DeliveryGuarantee::ReliableUnordered,
+            DeliveryGuarantee::ReliableOrdered,
+            DeliveryGuarantee::ReliableSequenced,
+        ] {
+            let packet = build_laminar_packet(payload.clone(), addr(), guarantee, Some(0));
+            assert_eq!(packet.addr(), addr());
+            assert_eq!(packet.payload(), payload.as_slice());
+        }
+    }
+
+    #[test]
+    fn token_bucket_admits_a_brand_new_address_immediately() {
+        let mut bucket = TokenBucket::new(3);
+        assert!(bucket.try_consume(3, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn token_bucket_rejects_once_capacity_is_exhausted() {
+        let mut bucket = TokenBucket::new(2);
+        let window = Duration::from_secs(60);
+        assert!(bucket.try_consume(2, window));
+        assert!(bucket.try_consume(2, window));
+        assert!(!bucket.try_consume(2, window));
+    }
+
+    #[test]
+    fn token_bucket_refills_after_the_window_elapses() {
+        let mut bucket = TokenBucket::new(1);
+        let window = Duration::from_millis(10);
+        assert!(bucket.try_consume(1, window));
+        assert!(!bucket.try_consume(1, window));
+        thread::sleep(window * 2);
+        assert!(bucket.try_consume(1, window));
+    }
+
+    #[test]
+    fn token_bucket_is_stale_once_its_window_is_long_past() {
+        let bucket = TokenBucket::new(1);
+        let now = Instant::now();
+        assert!(!bucket.is_stale(now, Duration::from_secs(60)));
+        assert!(bucket.is_stale(now + Duration::from_secs(61), Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn should_admit_rejects_once_max_connections_reached() {
+        let mut system = test_system();
+        assert!(!system.should_admit(addr(), system.config.max_connections, true));
+    }
+
+    #[test]
+    fn should_admit_requires_handshake_when_configured() {
+        let mut system = test_system();
+        system.config.require_handshake = true;
+        assert!(!system.should_admit(addr(), 0, false));
+        assert!(system.should_admit(addr(), 0, true));
+    }
+
+    #[test]
+    fn should_admit_enforces_the_rate_limit_per_address() {
+        let mut system = test_system();
+        system.config.connection_rate_limit = 1;
+        assert!(system.should_admit(addr(), 0, true));
+        assert!(!system.should_admit(addr(), 0, true));
+        // A different address has its own bucket and isn't affected by the first one's limit.
+        assert!(system.should_admit(addr2(), 0, true));
+    }
+
+    #[test]
+    fn should_admit_rejects_when_the_acceptor_denies() {
+        struct DenyAll;
+        impl ConnectionAcceptor for DenyAll {
+            fn accept(&mut self, _addr: SocketAddr) -> bool {
+                false
+            }
+        }
+
+        let mut system = test_system();
+        system.acceptor = Box::new(DenyAll);
+        assert!(!system.should_admit(addr(), 0, true));
+    }
+
+    #[test]
+    fn is_idle_is_false_for_a_connection_with_no_recorded_traffic_yet() {
+        assert!(!is_idle(None, Instant::now(), Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn is_idle_true_once_the_timeout_has_elapsed_since_last_received() {
+        let last_received = Instant::now();
+        let timeout = Duration::from_secs(30);
+        assert!(!is_idle(Some(last_received), last_received + Duration::from_secs(29), timeout));
+        assert!(is_idle(Some(last_received), last_received + Duration::from_secs(30), timeout));
+    }
+
+    #[test]
+    fn is_due_for_heartbeat_is_true_before_any_heartbeat_was_ever_sent() {
+        assert!(is_due_for_heartbeat(None, Instant::now(), Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn is_due_for_heartbeat_true_once_the_interval_has_elapsed() {
+        let last_sent = Instant::now();
+        let interval = Duration::from_secs(5);
+        assert!(!is_due_for_heartbeat(Some(last_sent), last_sent + Duration::from_secs(4), interval));
+        assert!(is_due_for_heartbeat(Some(last_sent), last_sent + Duration::from_secs(5), interval));
+    }
+
+    #[test]
+    fn tcp_transport_round_trips_a_length_prefixed_frame() {
+        let bind_addr: SocketAddr = "127.0.0.1:18790".parse().unwrap();
+        let config = ServerConfig {
+            udp_socket_addr: bind_addr,
+            ..test_config()
+        };
+        let transport = TcpTransport::start(&config).unwrap();
+        let receiver = transport.receiver();
+
+        let mut client = TcpStream::connect(bind_addr).unwrap();
+        let peer_addr = match receiver.recv_timeout(Duration::from_secs(2)).unwrap() {
+            TransportEvent::Connected(addr) => addr,
+            _ => panic!("expected a Connected event for the new client"),
+        };
+
+        transport.send(TransportPacket {
+            target: peer_addr,
+            payload: vec![9, 8, 7],
+            guarantee: DeliveryGuarantee::ReliableOrdered,
+            stream: None,
+        });
+
+        let mut len_buf = [0u8; 4];
+        client.read_exact(&mut len_buf).unwrap();
+        assert_eq!(u32::from_be_bytes(len_buf), 3);
+        let mut payload = [0u8; 3];
+        client.read_exact(&mut payload).unwrap();
+        assert_eq!(payload, [9, 8, 7]);
+    }
+}